@@ -2,16 +2,46 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 
+/// An error encountered while lexing or parsing, tagged with the
+/// source position it occurred at.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
 /// Useful wrapper for Peekable<Chars> which returns an EOF char
-/// when the iterator is empty
+/// when the iterator is empty. Tracks the current (line, column)
+/// position so errors can point back into the source.
 struct Stream<'a> {
-    stream: &'a mut Peekable<Chars<'a>>,
+    stream: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
 }
 
 impl<'a> Stream<'a> {
+    fn new(source: &'a str) -> Stream<'a> {
+        Stream {
+            stream: source.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
     fn peek(&mut self) -> char {
         let c: Option<&char> = self.stream.peek();
         match c {
@@ -20,7 +50,23 @@ impl<'a> Stream<'a> {
         }
     }
     fn next(&mut self) -> char {
-        self.stream.next().unwrap_or('\0')
+        let c = self.stream.next().unwrap_or('\0');
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        c
+    }
+    /// Build a ParseError for "expected `what`" at the stream's
+    /// current position.
+    fn expected_err(&self, what: &str) -> ParseError {
+        ParseError {
+            message: format!("expected {}", what),
+            line: self.line,
+            column: self.column,
+        }
     }
 }
 
@@ -45,13 +91,60 @@ fn scan_name(stream: &mut Stream) -> String {
 }
 
 /// Pull from stream into buffer until string is terminated or EOF
-/// reached
-fn scan_string(stream: &mut Stream) -> String {
+/// reached, interpreting backslash escape sequences as it goes
+fn scan_string(stream: &mut Stream) -> Result<String, ParseError> {
     let mut string = String::new();
     while stream.peek() != '\0' && stream.peek() != '"' {
-        string.push(stream.next());
+        if stream.peek() == '\\' {
+            stream.next();
+            string.push(scan_escape(stream)?);
+        } else {
+            string.push(stream.next());
+        }
+    }
+    Ok(string)
+}
+
+/// Consume one escape sequence (the characters after a `\`) and
+/// return the character it represents
+fn scan_escape(stream: &mut Stream) -> Result<char, ParseError> {
+    let c = stream.next();
+    match c {
+        'n' => Ok('\n'),
+        't' => Ok('\t'),
+        'r' => Ok('\r'),
+        '\\' => Ok('\\'),
+        '"' => Ok('"'),
+        '0' => Ok('\0'),
+        'x' => {
+            let mut hex = String::new();
+            for _ in 0..2 {
+                if stream.peek() == '"' || stream.peek() == '\0' {
+                    return Err(stream.expected_err("two hex digits after '\\x'"));
+                }
+                hex.push(stream.next());
+            }
+            u8::from_str_radix(&hex, 16)
+                .map(|b| b as char)
+                .map_err(|_| stream.expected_err("two hex digits after '\\x'"))
+        }
+        'u' => {
+            if stream.next() != '{' {
+                return Err(stream.expected_err("'{' after '\\u'"));
+            }
+            let mut hex = String::new();
+            while stream.peek() != '}' && stream.peek() != '\0' {
+                hex.push(stream.next());
+            }
+            if stream.next() != '}' {
+                return Err(stream.expected_err("closing '}' after '\\u{...}'"));
+            }
+            let code = u32::from_str_radix(&hex, 16)
+                .map_err(|_| stream.expected_err("hex digits inside '\\u{...}'"))?;
+            char::from_u32(code).ok_or_else(|| stream.expected_err("a valid Unicode scalar in '\\u{...}'"))
+        }
+        _ => Err(stream.expected_err("a recognized escape sequence")),
     }
-    return string;
 }
 
 #[derive(Debug)]
@@ -62,24 +155,70 @@ enum Number {
 }
 
 /// Read int/float from stream. Returns Number::NotANumber if
-/// scanning fails.
+/// scanning fails. Recognizes `0x`/`0o`/`0b` radix prefixes, `_`
+/// digit separators, and `e`/`E` scientific notation (which forces
+/// the result to a Number::Float).
 fn scan_number(stream: &mut Stream) -> Number {
     let mut buffer = String::new();
     let mut fractional: bool = false;
     if stream.peek() == '-' || stream.peek() == '+' {
         buffer.push(stream.next());
     }
-    while stream.peek().is_numeric() || stream.peek() == '.' {
+
+    if stream.peek() == '0' {
+        let zero = stream.next();
+        let radix = match stream.peek() {
+            'x' => Some(16),
+            'o' => Some(8),
+            'b' => Some(2),
+            _ => None,
+        };
+        if let Some(radix) = radix {
+            stream.next();
+            let mut digits = String::new();
+            while stream.peek().is_alphanumeric() || stream.peek() == '_' {
+                let c = stream.next();
+                if c != '_' {
+                    digits.push(c);
+                }
+            }
+            return match i64::from_str_radix(&digits, radix) {
+                Ok(n) => Number::Integer(if buffer.starts_with('-') { -n } else { n }),
+                Err(_) => Number::NotANumber,
+            };
+        }
+        buffer.push(zero);
+    }
+
+    while stream.peek().is_numeric() || stream.peek() == '.' || stream.peek() == '_' {
         if stream.peek() == '.' {
             fractional = true;
         }
+        let c = stream.next();
+        if c != '_' {
+            buffer.push(c);
+        }
+    }
+
+    if stream.peek() == 'e' || stream.peek() == 'E' {
+        fractional = true;
         buffer.push(stream.next());
+        if stream.peek() == '+' || stream.peek() == '-' {
+            buffer.push(stream.next());
+        }
+        while stream.peek().is_numeric() || stream.peek() == '_' {
+            let c = stream.next();
+            if c != '_' {
+                buffer.push(c);
+            }
+        }
     }
+
     if fractional {
         let result = buffer.parse::<f64>();
         match result {
-            Ok(ok) => Number::Float(ok),
-            Err(_) => Number::NotANumber,
+            Ok(ok) if ok.is_finite() => Number::Float(ok),
+            _ => Number::NotANumber,
         }
     } else {
         let result = buffer.parse::<i64>();
@@ -92,7 +231,7 @@ fn scan_number(stream: &mut Stream) -> Number {
 
 /// Central part of lexer. Advances stream by arbitrary amount
 /// until the next token is lexed.
-fn next_token(stream: &mut Stream) -> Result<Token, String> {
+fn next_token(stream: &mut Stream) -> Result<Token, ParseError> {
     let c = stream.peek();
     if c.is_whitespace() {
         stream.next();
@@ -106,7 +245,7 @@ fn next_token(stream: &mut Stream) -> Result<Token, String> {
         return match num {
             Number::Integer(n) => Ok(Token::IntLiteral(n)),
             Number::Float(f) => Ok(Token::FloatLiteral(f)),
-            Number::NotANumber => Err("Unable to parse literal".to_string()),
+            Number::NotANumber => Err(stream.expected_err("a valid number literal")),
         };
     }
     match c {
@@ -123,20 +262,20 @@ fn next_token(stream: &mut Stream) -> Result<Token, String> {
             if stream.next() == '=' {
                 Ok(Token::Assignment)
             } else {
-                Err("Expected = after :".to_string())
+                Err(stream.expected_err("'=' after ':'"))
             }
         }
         '"' => {
             stream.next();
-            let s = Token::StringLiteral(scan_string(stream));
+            let s = Token::StringLiteral(scan_string(stream)?);
             if stream.next() == '"' {
                 Ok(s)
             } else {
-                Err("String literal unterminated".to_string())
+                Err(stream.expected_err("closing '\"'"))
             }
         }
         '\0' => Ok(Token::EOF),
-        _ => Err("Unrecognized char".to_string()),
+        _ => Err(stream.expected_err("a recognized token")),
     }
 }
 
@@ -145,6 +284,56 @@ pub enum Value {
     String(String),
     Integer(i64),
     Float(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "\"{}\"", escape_string(s)),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Float(n) => {
+                assert!(n.is_finite(), "cannot serialize non-finite float `{}`", n);
+                let s = format!("{}", n);
+                if s.contains('.') || s.contains('e') || s.contains('E') {
+                    write!(f, "{}", s)
+                } else {
+                    write!(f, "{}.0", s)
+                }
+            }
+            Value::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Escape backslashes, quotes and NUL (which scan_string would
+/// otherwise read as an escape sequence or terminator), plus
+/// newline, tab and carriage return, using scan_escape's sequences
+fn escape_string(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Whether `name` consists only of characters scan_name is able to
+/// read back (i.e. it would round-trip through the parser)
+fn is_legal_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| !c.is_whitespace() && c != ':' && c != '\0')
 }
 
 #[derive(Debug)]
@@ -158,7 +347,7 @@ struct Declaration {
 #[derive(Debug)]
 enum ParseResult {
     Ok(Declaration),
-    Err(String),
+    Err(ParseError),
     EOF,
 }
 
@@ -175,9 +364,7 @@ fn parse_declaration(stream: &mut Stream) -> ParseResult {
             Ok(ok) => match ok {
                 Token::Name(s) => decl.name = s,
                 Token::EOF => return ParseResult::EOF,
-                _ => {
-                    return ParseResult::Err("Expected name at beginning of declaration".to_string())
-                }
+                _ => return ParseResult::Err(stream.expected_err("name at beginning of declaration")),
             },
             Err(e) => return ParseResult::Err(e),
         }
@@ -187,7 +374,7 @@ fn parse_declaration(stream: &mut Stream) -> ParseResult {
         match token {
             Ok(ok) => match ok {
                 Token::Assignment => (),
-                _ => return ParseResult::Err("Expected := after name in declaration".to_string()),
+                _ => return ParseResult::Err(stream.expected_err("':=' after name in declaration")),
             },
             Err(e) => return ParseResult::Err(e),
         }
@@ -205,7 +392,13 @@ fn parse_declaration(stream: &mut Stream) -> ParseResult {
                 Token::FloatLiteral(f) => {
                     decl.value = Value::Float(f);
                 }
-                _ => return ParseResult::Err("Expected literal at end of declaration".to_string()),
+                Token::Name(ref s) if s == "true" => {
+                    decl.value = Value::Bool(true);
+                }
+                Token::Name(ref s) if s == "false" => {
+                    decl.value = Value::Bool(false);
+                }
+                _ => return ParseResult::Err(stream.expected_err("literal at end of declaration")),
             },
             Err(e) => return ParseResult::Err(e),
         }
@@ -213,24 +406,85 @@ fn parse_declaration(stream: &mut Stream) -> ParseResult {
     ParseResult::Ok(decl)
 }
 
+/// Lazily parses declarations from a source string one at a time,
+/// without buffering the whole file up front. Ends the iterator on
+/// EOF and short-circuits on the first error.
+pub struct Parser<'a> {
+    stream: Stream<'a>,
+    failed: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a str) -> Parser<'a> {
+        Parser {
+            stream: Stream::new(source),
+            failed: false,
+        }
+    }
+    /// Current (line, column) position in the source
+    pub fn position(&self) -> (usize, usize) {
+        (self.stream.line, self.stream.column)
+    }
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Result<(String, Value), ParseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match parse_declaration(&mut self.stream) {
+            ParseResult::Ok(ok) => Some(Ok((ok.name, ok.value))),
+            ParseResult::EOF => None,
+            ParseResult::Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 /// Reads as many declarations from a source string as it can and
-/// stores them in a HashMap
-pub fn parse_vars(source: &str) -> Result<HashMap<String, Value>, String> {
-    let mut stream = Stream {
-        stream: &mut source.chars().peekable(),
-    };
+/// returns them as a Vec in source order, including repeated names
+pub fn parse_vars_ordered(source: &str) -> Result<Vec<(String, Value)>, ParseError> {
+    Parser::new(source).collect()
+}
+
+/// Reads as many declarations from a source string as it can and
+/// stores them in a HashMap. Returns a ParseError if a name is
+/// declared more than once.
+pub fn parse_vars(source: &str) -> Result<HashMap<String, Value>, ParseError> {
+    let mut parser = Parser::new(source);
     let mut decls: HashMap<String, Value> = HashMap::new();
-    loop {
-        let result = parse_declaration(&mut stream);
-        match result {
-            ParseResult::Ok(ok) => decls.insert(ok.name, ok.value),
-            ParseResult::EOF => break,
-            ParseResult::Err(e) => return Err(e),
-        };
+    for item in &mut parser {
+        let (name, value) = item?;
+        if decls.contains_key(&name) {
+            let (line, column) = parser.position();
+            return Err(ParseError {
+                message: format!("duplicate declaration of `{}`", name),
+                line,
+                column,
+            });
+        }
+        decls.insert(name, value);
     }
     Ok(decls)
 }
 
+/// Serializes a set of declarations back into vars-file source,
+/// one `name := value` per line, such that it round-trips through
+/// parse_vars. Panics if a name contains characters scan_name can't
+/// read back, or if a Value::Float is infinite or NaN (scan_number
+/// can never produce one, so this only fires on a hand-built Value).
+pub fn to_string(vars: &HashMap<String, Value>) -> String {
+    let mut out = String::new();
+    for (name, value) in vars {
+        assert!(is_legal_name(name), "illegal declaration name `{}`", name);
+        out.push_str(&format!("{} := {}\n", name, value));
+    }
+    out
+}
+
 #[test]
 fn test_parsing() {
     let source = "
@@ -240,7 +494,7 @@ fn test_parsing() {
 		variable_float := 105.3";
     let vars = match parse_vars(source) {
         Ok(ok) => ok,
-        Err(e) => panic!(e),
+        Err(e) => panic!("{}", e),
     };
     {
         let key: String = "variable_str".to_string();
@@ -264,3 +518,138 @@ fn test_parsing() {
         }
     }
 }
+
+#[test]
+fn test_parse_error_position() {
+    let source = "good := 1\nbad := @";
+    let err = match parse_vars(source) {
+        Ok(_) => panic!("unrecognized '@' token should not parse"),
+        Err(e) => e,
+    };
+    assert_eq!(err.line, 2);
+    assert_eq!(err.column, 8);
+}
+
+#[test]
+fn test_bool_literals() {
+    let source = "enabled := true\ndisabled := false\ntrue := 1";
+    let vars = parse_vars(source).expect("bool literals should parse");
+    match vars.get("enabled").expect("Name didn't get parsed correctly") {
+        Value::Bool(b) => assert!(*b),
+        _ => panic!("`true` didn't parse as Value::Bool"),
+    }
+    match vars.get("disabled").expect("Name didn't get parsed correctly") {
+        Value::Bool(b) => assert!(!*b),
+        _ => panic!("`false` didn't parse as Value::Bool"),
+    }
+    match vars.get("true").expect("`true` should still be usable as a declaration name") {
+        Value::Integer(n) => assert_eq!(*n, 1),
+        _ => panic!("declaration named `true` didn't parse correctly"),
+    }
+}
+
+#[test]
+fn test_string_escapes() {
+    let source = "a := \"line\\nbreak\"\nb := \"\\x41\\x42\"\nc := \"\\u{1f600}\"";
+    let vars = parse_vars(source).expect("escape sequences should parse");
+    match vars.get("a").expect("Name didn't get parsed correctly") {
+        Value::String(s) => assert_eq!(s, "line\nbreak"),
+        _ => panic!("expected Value::String"),
+    }
+    match vars.get("b").expect("Name didn't get parsed correctly") {
+        Value::String(s) => assert_eq!(s, "AB"),
+        _ => panic!("expected Value::String"),
+    }
+    match vars.get("c").expect("Name didn't get parsed correctly") {
+        Value::String(s) => assert_eq!(s, "\u{1f600}"),
+        _ => panic!("expected Value::String"),
+    }
+}
+
+#[test]
+fn test_invalid_escape_is_a_parse_error() {
+    assert!(parse_vars("a := \"\\q\"").is_err());
+}
+
+#[test]
+fn test_extended_numeric_syntax() {
+    let source = "hex := 0xFF\noct := 0o17\nbin := 0b101\nbig := 1_000_000\nsci := 6.02e23";
+    let vars = parse_vars(source).expect("extended numeric syntax should parse");
+    match vars.get("hex").expect("Name didn't get parsed correctly") {
+        Value::Integer(n) => assert_eq!(*n, 0xFF),
+        _ => panic!("expected Value::Integer"),
+    }
+    match vars.get("oct").expect("Name didn't get parsed correctly") {
+        Value::Integer(n) => assert_eq!(*n, 0o17),
+        _ => panic!("expected Value::Integer"),
+    }
+    match vars.get("bin").expect("Name didn't get parsed correctly") {
+        Value::Integer(n) => assert_eq!(*n, 0b101),
+        _ => panic!("expected Value::Integer"),
+    }
+    match vars.get("big").expect("Name didn't get parsed correctly") {
+        Value::Integer(n) => assert_eq!(*n, 1_000_000),
+        _ => panic!("expected Value::Integer"),
+    }
+    match vars.get("sci").expect("Name didn't get parsed correctly") {
+        Value::Float(f) => assert_eq!(*f, 6.02e23),
+        _ => panic!("expected Value::Float"),
+    }
+}
+
+#[test]
+fn test_duplicate_key_is_rejected() {
+    assert!(parse_vars("a := 1\na := 2").is_err());
+}
+
+#[test]
+fn test_parse_vars_ordered_preserves_order() {
+    let source = "c := 1\na := 2\nb := 3";
+    let decls = parse_vars_ordered(source).expect("should parse");
+    let names: Vec<&str> = decls.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["c", "a", "b"]);
+}
+
+#[test]
+fn test_parser_short_circuits_on_error() {
+    let source = "a := 1\nb := @\nc := 3";
+    let mut parser = Parser::new(source);
+    match parser.next() {
+        Some(Ok((name, Value::Integer(n)))) => {
+            assert_eq!(name, "a");
+            assert_eq!(n, 1);
+        }
+        other => panic!("expected first declaration to parse, got {:?}", other),
+    }
+    assert!(
+        matches!(parser.next(), Some(Err(_))),
+        "unrecognized token should surface as an error"
+    );
+    assert!(parser.next().is_none(), "parser should end after the first error");
+
+    // Callers that collect into a Result short-circuit at the first
+    // error instead of silently skipping past it to `c`.
+    let result: Result<Vec<(String, Value)>, ParseError> = Parser::new(source).collect();
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_round_trip_through_to_string() {
+    let source = "s := \"hello \\\"world\\\"\\n\"\ni := -42\nf := 3.5\nb := true";
+    let vars = parse_vars(source).expect("should parse");
+    let serialized = to_string(&vars);
+    let round_tripped = parse_vars(&serialized).expect("serialized output should reparse");
+    assert_eq!(vars.len(), round_tripped.len());
+    for (name, value) in &vars {
+        let other = round_tripped
+            .get(name)
+            .unwrap_or_else(|| panic!("`{}` missing after round trip", name));
+        match (value, other) {
+            (Value::String(a), Value::String(b)) => assert_eq!(a, b),
+            (Value::Integer(a), Value::Integer(b)) => assert_eq!(a, b),
+            (Value::Float(a), Value::Float(b)) => assert_eq!(a, b),
+            (Value::Bool(a), Value::Bool(b)) => assert_eq!(a, b),
+            _ => panic!("`{}` changed Value variant across round trip", name),
+        }
+    }
+}